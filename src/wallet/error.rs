@@ -0,0 +1,61 @@
+//! Errors surfaced by the wallet utilities.
+
+use std::{fmt, io};
+
+use blockchain;
+use super::state::{state, log};
+
+#[derive(Debug)]
+pub enum Error {
+    /// Filesystem operation on the wallet LOG, its cipher header or the
+    /// wallet config failed.
+    Io(io::Error),
+    /// Walking the blockchain to build the transaction iterator failed.
+    BlockchainIterator(blockchain::Error),
+    /// A `State` fold (`forward_with_utxos`, `forward_with_txins`,
+    /// `update_with_logs`, ...) rejected an entry.
+    WalletState(state::Error),
+    /// The plain (unencrypted) LOG reader/writer failed.
+    WalletLog(log::Error),
+    /// Another process already holds the wallet LOG lock; carries its pid.
+    WalletLogLocked(u32),
+    /// The LOG is encrypted and this session has no cached key for it yet;
+    /// run `unlock` first.
+    WalletLogNeedsUnlock,
+    /// `encrypt` was called on a LOG that already has a cipher header.
+    WalletLogAlreadyEncrypted,
+    /// `unlock`/`decrypt` was called on a LOG that has no cipher header.
+    WalletLogNotEncrypted,
+    /// A sealed LOG record did not open under the session key (wrong
+    /// password, or the record was tampered with).
+    WalletLogDecryption,
+    /// A LOG record or the LOG cipher header decrypted/read but was not
+    /// valid: a truncated record, bad JSON, an invalid salt, or a failed
+    /// key derivation.
+    WalletLogCorrupted(String),
+    /// The wallet has no blockchain attached to sync against.
+    WalletNotAttached,
+    /// The wallet's tracked UTxOs cannot cover the requested spend.
+    InsufficientFunds,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "wallet I/O error: {}", err),
+            Error::BlockchainIterator(err) => write!(f, "failed to walk the blockchain: {}", err),
+            Error::WalletState(err) => write!(f, "invalid wallet state transition: {}", err),
+            Error::WalletLog(err) => write!(f, "wallet LOG error: {}", err),
+            Error::WalletLogLocked(pid) => write!(f, "wallet LOG is locked by another process (pid {})", pid),
+            Error::WalletLogNeedsUnlock => write!(f, "wallet LOG is encrypted, run `unlock` first"),
+            Error::WalletLogAlreadyEncrypted => write!(f, "wallet LOG is already encrypted"),
+            Error::WalletLogNotEncrypted => write!(f, "wallet LOG is not encrypted"),
+            Error::WalletLogDecryption => write!(f, "wallet LOG record could not be decrypted"),
+            Error::WalletLogCorrupted(err) => write!(f, "wallet LOG record is corrupted: {}", err),
+            Error::WalletNotAttached => write!(f, "wallet has no blockchain attached"),
+            Error::InsufficientFunds => write!(f, "insufficient funds to cover the requested spend"),
+        }
+    }
+}
+
+impl ::std::error::Error for Error {}
@@ -0,0 +1,83 @@
+//! Wallet configuration.
+//!
+//! The bits of `Wallet` that this crate's own modules need to read or
+//! persist live here. The rest of `Wallet` (encrypted spending key
+//! storage, the LOG file/lock, the wallet name) is out of scope for this
+//! file and is not reproduced here.
+
+use std::{fs, path::PathBuf};
+use cardano::hdwallet::XPub;
+
+pub mod error;
+pub mod utils;
+
+use self::error::Error;
+
+/// The part of a wallet's config that is safe to keep on disk in the
+/// clear. `*_account_public_key` is the account-level extended public
+/// key (chain code + public key) -- enough for a watch-only sync to
+/// recognize funds, never enough to spend them.
+///
+/// Note: `load_bip44_lookup_structure`/`load_randomindex_lookup_structure`
+/// (in [`utils`]) resolve a cached key here into a
+/// `lookup::sequentialindex::SequentialBip44Lookup`/
+/// `lookup::randomindex::RandomIndexLookup` via their `from_public_key`
+/// constructors. Those lookup types live in `super::state::lookup`, a
+/// module this snapshot does not include (along with the rest of
+/// `super::state`), so that half of the watch-only path cannot be
+/// reproduced here; this file only carries the config side.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WalletConfig {
+    pub bip44_account_public_key: Option<XPub>,
+    pub rindex_account_public_key: Option<XPub>,
+}
+
+pub struct Wallet {
+    root_dir: PathBuf,
+    config: WalletConfig,
+}
+
+impl Wallet {
+    fn config_path(&self) -> PathBuf { self.root_dir.join("config.json") }
+
+    fn save_config(&self) -> Result<(), Error> {
+        let bytes = ::serde_json::to_vec_pretty(&self.config).expect("wallet config is always serializable");
+        fs::write(self.config_path(), bytes).map_err(Error::Io)
+    }
+
+    /// Construct a fresh bip44 wallet's config, persisting its
+    /// account-level extended public key immediately so a watch-only
+    /// sync can run right away -- the spending key itself is stored
+    /// separately, encrypted, and is never touched by this path.
+    pub fn create_bip44(root_dir: PathBuf, account_public_key: XPub) -> Result<Wallet, Error> {
+        let mut wallet = Wallet { root_dir, config: WalletConfig::default() };
+        self::utils::persist_bip44_account_public_key(&mut wallet, account_public_key)?;
+        Ok(wallet)
+    }
+
+    /// Construct a fresh random-index wallet's config, mirroring
+    /// [`Wallet::create_bip44`].
+    pub fn create_rindex(root_dir: PathBuf, account_public_key: XPub) -> Result<Wallet, Error> {
+        let mut wallet = Wallet { root_dir, config: WalletConfig::default() };
+        self::utils::persist_rindex_account_public_key(&mut wallet, account_public_key)?;
+        Ok(wallet)
+    }
+
+    pub fn bip44_account_public_key(&self) -> Option<XPub> {
+        self.config.bip44_account_public_key.clone()
+    }
+
+    pub fn rindex_account_public_key(&self) -> Option<XPub> {
+        self.config.rindex_account_public_key.clone()
+    }
+
+    pub fn set_bip44_account_public_key(&mut self, public_key: XPub) -> Result<(), Error> {
+        self.config.bip44_account_public_key = Some(public_key);
+        self.save_config()
+    }
+
+    pub fn set_rindex_account_public_key(&mut self, public_key: XPub) -> Result<(), Error> {
+        self.config.rindex_account_public_key = Some(public_key);
+        self.save_config()
+    }
+}
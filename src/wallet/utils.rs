@@ -8,24 +8,50 @@ use super::{Wallet};
 use super::state::{log, state, lookup, iter::TransactionIterator, utxo::UTxO, ptr::{StatePtr}};
 use super::error::{Error};
 
-use std::{path::PathBuf, io::Write};
-use cardano::{address::ExtendedAddr, block::{BlockDate}};
+use std::{path::PathBuf, io::{Write, Read}};
+use cardano::{address::ExtendedAddr, block::{BlockDate}, coin::Coin, hdwallet::XPub
+             , tx::{Tx, TxId, TxIn, TxOut, TxInWitness, TxAux}
+             , fee::{FeeAlgorithm, LinearFee}
+             };
 
 use utils::{term::{Term, style::{Style}}};
 
 use blockchain::{Blockchain};
 use serde;
+use serde_json;
+use sodiumoxide::crypto::{pwhash, secretbox};
+
+/// Whether `ptr` is still an ancestor of the blockchain's current tip:
+/// the block the blockchain actually has at `ptr`'s date must be the
+/// same block `ptr` recorded, by hash. Unlike probing
+/// `Blockchain::iter_to_tip(ptr.latest_known_hash)` and treating any
+/// `Err` as "orphaned", this can't be tripped by a transient I/O error
+/// elsewhere in the chain -- it only looks at the one block `ptr` claims
+/// to know about.
+fn is_known_ancestor_of_tip(blockchain: &Blockchain, ptr: &StatePtr) -> bool {
+    match ptr.latest_addr {
+        None => true,
+        Some(date) => blockchain.block_at(date)
+            .map(|block| block.id() == ptr.latest_known_hash)
+            .unwrap_or(false),
+    }
+}
 
 pub fn update_wallet_state_with_utxos<LS>( term: &mut Term
                                          , wallet: &Wallet
                                          , blockchain: &Blockchain
                                          , state: &mut state::State<LS>
-                                         )
+                                         ) -> Result<(), Error>
     where LS: lookup::AddressLookup<AddressInput = ExtendedAddr>
         , for<'de> LS::AddressOutput : serde::Deserialize<'de> + serde::Serialize + Clone + ::std::fmt::Debug
 {
     let blockchain_tip = blockchain.load_tip().0;
 
+    if !is_known_ancestor_of_tip(blockchain, state.ptr()) {
+        term.info("local wallet tip was orphaned by a chain reorganization, rolling back...\n").unwrap();
+        rollback_to_last_common_checkpoint(wallet, blockchain, state)?;
+    }
+
     let from_ptr = state.ptr().clone();
     let from = from_ptr.latest_known_hash;
     let from_date = from_ptr.latest_addr.unwrap_or(BlockDate::Genesis(0));
@@ -36,17 +62,17 @@ pub fn update_wallet_state_with_utxos<LS>( term: &mut Term
     let progress = term.progress_bar(num_blocks as u64);
     progress.set_message("loading transactions... ");
 
+    let iter = blockchain.iter_to_tip(from).map_err(Error::BlockchainIterator)?;
     let mut last_block_date = from_date;
-    for res in TransactionIterator::new(progress, blockchain.iter_to_tip(from).unwrap() /* BAD */) {
-        let (ptr, txaux) = res.unwrap(); // BAD
+    for res in TransactionIterator::new(progress, iter) {
+        let (ptr, txaux) = res.map_err(Error::BlockchainIterator)?;
 
         if let Some(addr) = ptr.latest_addr {
             if last_block_date.get_epochid() != addr.get_epochid() {
 
-                let log_lock = lock_wallet_log(&wallet);
-                let mut writer = log::LogWriter::open(log_lock).unwrap();
+                let mut writer = open_log_writer::<ExtendedAddr>(&wallet)?;
                 let log : log::Log<ExtendedAddr> = log::Log::Checkpoint(ptr.clone());
-                writer.append(&log).unwrap();
+                writer.append(&log)?;
             }
 
             last_block_date = addr.clone();
@@ -55,10 +81,9 @@ pub fn update_wallet_state_with_utxos<LS>( term: &mut Term
         {
             let logs = state.forward_with_txins(
                 txaux.tx.inputs.iter().map(|txin| (ptr.clone(), txin))
-            ).unwrap();
-            let log_lock = lock_wallet_log(&wallet);
-            let mut writer = log::LogWriter::open(log_lock).unwrap();
-            for log in logs { writer.append(&log).unwrap(); }
+            ).map_err(Error::WalletState)?;
+            let mut writer = open_log_writer::<ExtendedAddr>(&wallet)?;
+            for log in logs { writer.append(&log)?; }
         }
 
         {
@@ -74,36 +99,93 @@ pub fn update_wallet_state_with_utxos<LS>( term: &mut Term
                       }
                     )
                 })
-            ).unwrap();
+            ).map_err(Error::WalletState)?;
+
+            let mut writer = open_log_writer::<ExtendedAddr>(&wallet)?;
+            for log in logs { writer.append(&log)?; }
+        }
+    }
+
+    Ok(())
+}
+
+/// Recover from a chain reorganization: walk the LOG backwards from its
+/// most recent entry to the last [`log::Log::Checkpoint`] that is still
+/// an ancestor of the blockchain's current tip, undo every
+/// `ReceivedFund`/`SpentFund` recorded after it, and rewrite the LOG to
+/// stop there. Leaves `state` and the on-disk LOG agreeing on that
+/// checkpoint so the caller can resume a normal forward sync from it.
+fn rollback_to_last_common_checkpoint<LS>( wallet: &Wallet
+                                          , blockchain: &Blockchain
+                                          , state: &mut state::State<LS>
+                                          ) -> Result<(), Error>
+    where LS: lookup::AddressLookup
+        , for<'de> LS::AddressOutput : serde::Deserialize<'de> + serde::Serialize + Clone
+{
+    let logs : Vec<log::Log<LS::AddressOutput>> = open_log_reader::<LS::AddressOutput>(wallet)?
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let keep_upto = logs.iter().enumerate()
+        .filter_map(|(i, entry)| match entry {
+            log::Log::Checkpoint(ptr) if is_known_ancestor_of_tip(blockchain, ptr) => Some(i + 1),
+            _ => None,
+        })
+        .last()
+        .unwrap_or(0);
 
-            let log_lock = lock_wallet_log(&wallet);
-            let mut writer = log::LogWriter::open(log_lock).unwrap();
-            for log in logs { writer.append(&log).unwrap(); }
+    // `forget_utxo`/`restore_utxo` are the inverse of what
+    // `forward_with_utxos`/`forward_with_txins` did when these entries were
+    // first recorded: drop a UTxO the reorg no longer confirms, or put back
+    // one that was spent on the now-orphaned branch.
+    for entry in logs[keep_upto..].iter().rev() {
+        match entry {
+            log::Log::ReceivedFund(_, utxo) => state.forget_utxo(utxo),
+            log::Log::SpentFund(_, utxo) => state.restore_utxo(utxo.clone()),
+            log::Log::Checkpoint(_) => {},
         }
     }
+
+    let retained_ptr = match keep_upto.checked_sub(1).and_then(|i| logs.get(i)) {
+        Some(log::Log::Checkpoint(ptr)) => ptr.clone(),
+        _ => StatePtr::default(),
+    };
+    state.set_ptr(retained_ptr);
+
+    let log_lock = lock_wallet_log(wallet)?;
+    let path = log_lock.path().to_path_buf();
+    drop(log_lock);
+    ::std::fs::remove_file(&path).map_err(Error::Io)?;
+
+    let mut writer = open_log_writer::<LS::AddressOutput>(wallet)?;
+    for log in &logs[..keep_upto] { writer.append(log)?; }
+
+    Ok(())
 }
 
+/// How [`display_wallet_state_logs`] renders the LOG: human-friendly
+/// (`Pretty`), a looser terminal dump (`Dump`), or stable JSONL for
+/// scripting (`Json`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogDisplayFormat { Pretty, Dump, Json }
+
 pub fn display_wallet_state_logs<LS>( term: &mut Term
                                     , wallet: &Wallet
                                     , _state: &mut state::State<LS>
-                                    , pretty: bool
-                                    )
+                                    , format: LogDisplayFormat
+                                    ) -> Result<(), Error>
     where LS: lookup::AddressLookup
         , for<'de> LS::AddressOutput : serde::Deserialize<'de>
 {
-    let log_lock = lock_wallet_log(&wallet);
-    let reader = log::LogReader::open(log_lock).unwrap();
-    let reader : log::LogIterator<LS::AddressOutput> = reader.into_iter();
-    let reader = reader.filter_map(|r| {
-        match r {
-            Err(err) => {
-                panic!("{:?}", err)
-            },
-            Ok(v) => Some(v)
-        }
-    });
+    let reader = open_log_reader::<LS::AddressOutput>(&wallet)?;
 
     for log in reader {
+        let log = log?;
+        if format == LogDisplayFormat::Json {
+            writeln!(term, "{}", log_to_json(&log)).unwrap();
+            continue;
+        }
+
+        let pretty = format == LogDisplayFormat::Pretty;
         match log {
             log::Log::Checkpoint(ptr) => {
                 if ! pretty {
@@ -131,6 +213,42 @@ pub fn display_wallet_state_logs<LS>( term: &mut Term
             }
         }
     }
+
+    Ok(())
+}
+
+/// Serialize a single LOG entry to the stable JSONL schema consumed by
+/// scripting tools: `{"type", "block_date", "block_hash",
+/// "transaction_id", "index", "value"}`, with the last three left `null`
+/// for a checkpoint record.
+fn log_to_json<A>(log: &log::Log<A>) -> serde_json::Value {
+    match log {
+        log::Log::Checkpoint(ptr) => checkpoint_json(ptr),
+        log::Log::ReceivedFund(ptr, utxo) => utxo_json("received", ptr, utxo),
+        log::Log::SpentFund(ptr, utxo) => utxo_json("spent", ptr, utxo),
+    }
+}
+
+fn checkpoint_json(ptr: &StatePtr) -> serde_json::Value {
+    let mut map = serde_json::Map::new();
+    map.insert("type".to_string(), serde_json::Value::String("checkpoint".to_string()));
+    map.insert("block_date".to_string(), serde_json::Value::String(format!("{}", ptr.latest_block_date())));
+    map.insert("block_hash".to_string(), serde_json::Value::String(format!("{}", ptr.latest_known_hash)));
+    map.insert("transaction_id".to_string(), serde_json::Value::Null);
+    map.insert("index".to_string(), serde_json::Value::Null);
+    map.insert("value".to_string(), serde_json::Value::Null);
+    serde_json::Value::Object(map)
+}
+
+fn utxo_json<A>(kind: &str, ptr: &StatePtr, utxo: &UTxO<A>) -> serde_json::Value {
+    let mut map = serde_json::Map::new();
+    map.insert("type".to_string(), serde_json::Value::String(kind.to_string()));
+    map.insert("block_date".to_string(), serde_json::Value::String(format!("{}", ptr.latest_block_date())));
+    map.insert("block_hash".to_string(), serde_json::Value::String(format!("{}", ptr.latest_known_hash)));
+    map.insert("transaction_id".to_string(), serde_json::Value::String(format!("{}", utxo.transaction_id)));
+    map.insert("index".to_string(), serde_json::Value::Number(utxo.index_in_transaction.into()));
+    map.insert("value".to_string(), serde_json::Value::String(format!("{}", utxo.credited_value)));
+    serde_json::Value::Object(map)
 }
 
 pub fn display_utxo<L>(term: &mut Term, ptr: StatePtr, utxo: UTxO<L>, debit: bool) {
@@ -182,101 +300,631 @@ pub fn dump_utxo<L>(term: &mut Term, ptr: StatePtr, utxo: UTxO<L>, debit: bool)
 }
 
 
-pub fn update_wallet_state_with_logs<LS>(wallet: &Wallet, state: &mut state::State<LS>)
+pub fn update_wallet_state_with_logs<LS>(wallet: &Wallet, state: &mut state::State<LS>) -> Result<(), Error>
     where LS: lookup::AddressLookup
         , for<'de> LS::AddressOutput : serde::Deserialize<'de>
 {
-    let log_lock = lock_wallet_log(wallet);
-    state.update_with_logs(
-        log::LogReader::open(log_lock).unwrap() // BAD
-            .into_iter().filter_map(|r| {
-                match r {
-                    Err(err) => {
-                        panic!("{:?}", err)
-                    },
-                    Ok(v) => Some(v)
-                }
-            })
-    ).unwrap(); // BAD
+    let logs = open_log_reader::<LS::AddressOutput>(wallet)?.collect::<Result<Vec<_>, Error>>()?;
+    state.update_with_logs(logs).map_err(Error::WalletState)
+}
+
+/// Balance summary of a wallet: how much it holds, how much of that is
+/// actually spendable right now, and how far its LOG has been synced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletInfo {
+    pub total: Coin,
+    pub spendable: Coin,
+    pub number_of_utxos: usize,
+    pub last_synced: StatePtr,
 }
 
-pub fn load_bip44_lookup_structure(term: &mut Term, wallet: &Wallet) -> lookup::sequentialindex::SequentialBip44Lookup {
-    // TODO: to prevent from the need of the password, we can ask the user to create accounts ahead.
-    //       if we store the wallet's account public keys in the config file we may not need for the
-    //       password (and for the private key).
+/// Fold the wallet's `State`/LOG into a [`WalletInfo`]. With
+/// `refresh_from_node` set, syncs against the attached blockchain first;
+/// otherwise this is a purely local, non-interactive read of the LOG.
+pub fn aggregate_wallet_summary<LS>( term: &mut Term
+                                   , wallet: &Wallet
+                                   , blockchain: Option<&Blockchain>
+                                   , state: &mut state::State<LS>
+                                   , refresh_from_node: bool
+                                   ) -> Result<WalletInfo, Error>
+    where LS: lookup::AddressLookup<AddressInput = ExtendedAddr>
+        , for<'de> LS::AddressOutput : serde::Deserialize<'de> + serde::Serialize + Clone + ::std::fmt::Debug
+{
+    if refresh_from_node {
+        let blockchain = blockchain.ok_or(Error::WalletNotAttached)?;
+        update_wallet_state_with_utxos(term, wallet, blockchain, state)?;
+    } else {
+        update_wallet_state_with_logs(wallet, state)?;
+    }
+
+    let utxos : Vec<_> = state.utxos().collect();
+    let total = utxos.iter().fold(Coin::zero(), |acc, utxo| {
+        (acc + utxo.credited_value).expect("total wallet value cannot overflow Coin")
+    });
+
+    Ok(WalletInfo {
+        total,
+        // no UTxOs are reserved by a pending spend yet, so everything we
+        // hold is spendable; this will diverge once coin selection can
+        // hold inputs for an in-flight transaction.
+        spendable: total,
+        number_of_utxos: utxos.len(),
+        last_synced: state.ptr().clone(),
+    })
+}
+
+// Watch-only: if the wallet config was created with its account extended
+// public keys persisted, we can build the lookup structures straight from
+// them and never touch the encrypted spending key. This is the same idea
+// as an `ExtendedFullViewingKey` for shielded wallets: enough to recognize
+// and track funds, not enough to spend them.
+pub fn load_bip44_lookup_structure(term: &mut Term, wallet: &Wallet) -> Result<lookup::sequentialindex::SequentialBip44Lookup, Error> {
+    if let Some(public_key) = wallet.bip44_account_public_key() {
+        return Ok(lookup::sequentialindex::SequentialBip44Lookup::from_public_key(public_key));
+    }
+
     term.info("Enter the wallet password.\n").unwrap();
     let password = term.password("wallet password: ").unwrap();
 
-    let wallet = match wallet.get_wallet_bip44(password.as_bytes()) {
-        Err(Error::CannotRetrievePrivateKeyInvalidPassword) => {
-            term.error("Invalid wallet spending password").unwrap();
-            ::std::process::exit(1);
-        },
-        Err(Error::CannotRetrievePrivateKey(err)) => {
-            term.error(&format!("Cannot retrieve the private key of the wallet: {}", err)).unwrap();
-            term.info("The encrypted wallet password is in an invalid format. You might need to delete this wallet and recover it.").unwrap();
-            ::std::process::exit(1);
-        },
-        Err(err) => {
-            term.error(IMPOSSIBLE_HAPPENED).unwrap();
-            panic!("failing with an unexpected error {:#?}", err);
-        },
-        Ok(wallet) => { wallet }
-    };
-    lookup::sequentialindex::SequentialBip44Lookup::new(wallet)
+    let wallet = wallet.get_wallet_bip44(password.as_bytes())?;
+    Ok(lookup::sequentialindex::SequentialBip44Lookup::new(wallet))
 }
-pub fn load_randomindex_lookup_structure(term: &mut Term, wallet: &Wallet) -> lookup::randomindex::RandomIndexLookup {
-    // in the case of the random index, we may not need the password if we have the public key
+pub fn load_randomindex_lookup_structure(term: &mut Term, wallet: &Wallet) -> Result<lookup::randomindex::RandomIndexLookup, Error> {
+    if let Some(public_key) = wallet.rindex_account_public_key() {
+        return Ok(lookup::randomindex::RandomIndexLookup::from_public_key(public_key));
+    }
+
     term.info("Enter the wallet password.\n").unwrap();
     let password = term.password("wallet password: ").unwrap();
 
-    let wallet = match wallet.get_wallet_rindex(password.as_bytes()) {
-        Err(Error::CannotRetrievePrivateKeyInvalidPassword) => {
-            term.error("Invalid wallet spending password").unwrap();
-            ::std::process::exit(1);
-        },
-        Err(Error::CannotRetrievePrivateKey(err)) => {
-            term.error(&format!("Cannot retrieve the private key of the wallet: {}", err)).unwrap();
-            term.info("The encrypted wallet password is in an invalid format. You might need to delete this wallet and recover it.").unwrap();
-            ::std::process::exit(1);
-        },
-        Err(err) => {
-            term.error(IMPOSSIBLE_HAPPENED).unwrap();
-            panic!("failing with an unexpected error {:#?}", err);
-        },
-        Ok(wallet) => { wallet }
-    };
-    lookup::randomindex::RandomIndexLookup::from(wallet)
+    let wallet = wallet.get_wallet_rindex(password.as_bytes())?;
+    Ok(lookup::randomindex::RandomIndexLookup::from(wallet))
+}
+
+/// Persist the account-level extended public key (chain code + public key)
+/// for a bip44 wallet so future syncs can run watch-only, without prompting
+/// for the spending password.
+pub fn persist_bip44_account_public_key(wallet: &mut Wallet, public_key: XPub) -> Result<(), Error> {
+    wallet.set_bip44_account_public_key(public_key)
+}
+
+/// Persist the account-level extended public key for a random-index wallet,
+/// mirroring [`persist_bip44_account_public_key`].
+pub fn persist_rindex_account_public_key(wallet: &mut Wallet, public_key: XPub) -> Result<(), Error> {
+    wallet.set_rindex_account_public_key(public_key)
+}
+
+pub fn lock_wallet_log(wallet: &Wallet) -> Result<log::LogLock, Error> {
+    wallet.log()
+}
+
+/// At-rest encryption for the wallet LOG.
+///
+/// Every record is sealed independently with XSalsa20-Poly1305
+/// (`secretbox`), under a key derived from the LOG password with
+/// libsodium's `pwhash` (argon2id). A small header file next to the LOG
+/// stores only the KDF salt and cost parameters, never the key itself, so
+/// the key can always be re-derived from the password alone. The derived
+/// key itself never touches disk: `cache_session_key` only holds it in a
+/// process-lifetime, in-memory table, so a stolen disk image is useless
+/// without the password even if a session had the LOG unlocked recently.
+mod log_cipher {
+    use sodiumoxide::crypto::{pwhash, secretbox};
+    use std::{collections::HashMap, fs, io, path::{Path, PathBuf}, sync::Mutex};
+    use lazy_static::lazy_static;
+
+    #[derive(Serialize, Deserialize)]
+    struct Header { salt: Vec<u8>, opslimit: usize, memlimit: usize }
+
+    lazy_static! {
+        static ref SESSION_KEYS: Mutex<HashMap<PathBuf, secretbox::Key>> = Mutex::new(HashMap::new());
+    }
+
+    fn header_path(log_path: &Path) -> PathBuf { log_path.with_extension("cipher") }
+
+    pub fn is_encrypted(log_path: &Path) -> bool { header_path(log_path).exists() }
+
+    fn derive_key(password: &[u8], header: &Header) -> Result<secretbox::Key, super::Error> {
+        let salt = pwhash::Salt::from_slice(&header.salt)
+            .ok_or_else(|| super::Error::WalletLogCorrupted("invalid salt in LOG cipher header".to_string()))?;
+        let mut key_bytes = [0u8; secretbox::KEYBYTES];
+        pwhash::derive_key(
+            &mut key_bytes, password, &salt,
+            pwhash::OpsLimit(header.opslimit), pwhash::MemLimit(header.memlimit)
+        ).map_err(|()| super::Error::WalletLogCorrupted("argon2id key derivation failed".to_string()))?;
+        Ok(secretbox::Key(key_bytes))
+    }
+
+    /// Pick a new salt, persist the header and derive the key for it.
+    pub fn set_password(log_path: &Path, password: &[u8]) -> Result<secretbox::Key, super::Error> {
+        let header = Header {
+            salt: pwhash::gen_salt().0.to_vec(),
+            opslimit: (pwhash::OPSLIMIT_INTERACTIVE.0),
+            memlimit: (pwhash::MEMLIMIT_INTERACTIVE.0),
+        };
+        let key = derive_key(password, &header)?;
+        let bytes = ::serde_json::to_vec(&header).expect("LOG cipher header is always serializable");
+        fs::write(header_path(log_path), bytes).map_err(super::Error::Io)?;
+        Ok(key)
+    }
+
+    /// Re-derive the key for an already-encrypted LOG from its password.
+    pub fn unlock(log_path: &Path, password: &[u8]) -> Result<secretbox::Key, super::Error> {
+        let bytes = fs::read(header_path(log_path)).map_err(super::Error::Io)?;
+        let header : Header = ::serde_json::from_slice(&bytes)
+            .map_err(|e| super::Error::WalletLogCorrupted(e.to_string()))?;
+        derive_key(password, &header)
+    }
+
+    pub fn remove_header(log_path: &Path) -> io::Result<()> {
+        let path = header_path(log_path);
+        if path.exists() { fs::remove_file(path) } else { Ok(()) }
+    }
+
+    /// Hold the derived key in memory for the rest of this process only.
+    pub fn cache_session_key(log_path: &Path, key: &secretbox::Key) {
+        SESSION_KEYS.lock().unwrap().insert(log_path.to_path_buf(), key.clone());
+    }
+
+    pub fn cached_session_key(log_path: &Path) -> Option<secretbox::Key> {
+        SESSION_KEYS.lock().unwrap().get(log_path).cloned()
+    }
+
+    pub fn clear_session_key(log_path: &Path) {
+        SESSION_KEYS.lock().unwrap().remove(log_path);
+    }
+
+    pub fn seal(key: &secretbox::Key, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = secretbox::gen_nonce();
+        let mut out = nonce.0.to_vec();
+        out.extend(secretbox::seal(plaintext, &nonce, key));
+        out
+    }
+
+    pub fn open(key: &secretbox::Key, sealed: &[u8]) -> Result<Vec<u8>, ()> {
+        if sealed.len() < secretbox::NONCEBYTES { return Err(()); }
+        let (nonce_bytes, ciphertext) = sealed.split_at(secretbox::NONCEBYTES);
+        let nonce = secretbox::Nonce::from_slice(nonce_bytes).ok_or(())?;
+        secretbox::open(ciphertext, &nonce, key)
+    }
+}
+
+/// A LOG writer that transparently seals each record when the LOG is
+/// encrypted, and falls through to the plain [`log::LogWriter`] otherwise.
+pub enum LogAppender<A> {
+    Plain(log::LogWriter),
+    Encrypted(EncryptedLogWriter<A>),
 }
 
-pub fn lock_wallet_log(wallet: &Wallet) -> log::LogLock {
-    match wallet.log() {
-        Err(Error::WalletLogAlreadyLocked(pid)) => {
-            error!("Wallet's LOG already locked by another process or thread ({})\n", pid);
-            ::std::process::exit(1);
-        },
-        Err(err) => {
-            error!("{}", IMPOSSIBLE_HAPPENED);
-            panic!("`lock_wallet_log' has failed with an unexpected error {:#?}", err);
-        },
-        Ok(lock) => { lock }
+impl<A: serde::Serialize> LogAppender<A> {
+    pub fn append(&mut self, log: &log::Log<A>) -> Result<(), Error> {
+        match self {
+            LogAppender::Plain(writer) => writer.append(log).map_err(Error::WalletLog),
+            LogAppender::Encrypted(writer) => writer.append(log),
+        }
     }
 }
 
-pub fn load_attached_blockchain(term: &mut Term, root_dir: PathBuf, name: Option<String>) -> Blockchain {
-    match name {
+fn open_log_writer<A: serde::Serialize>(wallet: &Wallet) -> Result<LogAppender<A>, Error> {
+    let log_lock = lock_wallet_log(wallet)?;
+    let path = log_lock.path().to_path_buf();
+    if log_cipher::is_encrypted(&path) {
+        let key = log_cipher::cached_session_key(&path).ok_or(Error::WalletLogNeedsUnlock)?;
+        Ok(LogAppender::Encrypted(EncryptedLogWriter::open(log_lock, key)?))
+    } else {
+        Ok(LogAppender::Plain(log::LogWriter::open(log_lock).map_err(Error::WalletLog)?))
+    }
+}
+
+/// A LOG reader counterpart to [`LogAppender`]: transparently decrypts
+/// when the LOG is encrypted, replays the plain [`log::LogIterator`]
+/// otherwise.
+pub enum LogScanner<A> {
+    Plain(log::LogIterator<A>),
+    Encrypted(EncryptedLogReader<A>),
+}
+
+impl<A> Iterator for LogScanner<A>
+    where for<'de> A: serde::Deserialize<'de>
+{
+    type Item = Result<log::Log<A>, Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            LogScanner::Plain(it) => it.next().map(|r| r.map_err(Error::WalletLog)),
+            LogScanner::Encrypted(it) => it.next(),
+        }
+    }
+}
+
+fn open_log_reader<A>(wallet: &Wallet) -> Result<LogScanner<A>, Error>
+    where for<'de> A: serde::Deserialize<'de>
+{
+    let log_lock = lock_wallet_log(wallet)?;
+    let path = log_lock.path().to_path_buf();
+    if log_cipher::is_encrypted(&path) {
+        let key = log_cipher::cached_session_key(&path).ok_or(Error::WalletLogNeedsUnlock)?;
+        Ok(LogScanner::Encrypted(EncryptedLogReader::open(log_lock, key)?))
+    } else {
+        Ok(LogScanner::Plain(log::LogReader::open(log_lock).map_err(Error::WalletLog)?.into_iter()))
+    }
+}
+
+pub struct EncryptedLogWriter<A> {
+    file: ::std::fs::File,
+    key: secretbox::Key,
+    _marker: ::std::marker::PhantomData<A>,
+}
+
+impl<A> EncryptedLogWriter<A> {
+    fn open(log_lock: log::LogLock, key: secretbox::Key) -> Result<EncryptedLogWriter<A>, Error> {
+        let file = ::std::fs::OpenOptions::new()
+            .create(true).append(true)
+            .open(log_lock.path())
+            .map_err(Error::Io)?;
+        Ok(EncryptedLogWriter { file, key, _marker: ::std::marker::PhantomData })
+    }
+}
+
+impl<A: serde::Serialize> EncryptedLogWriter<A> {
+    pub fn append(&mut self, log: &log::Log<A>) -> Result<(), Error> {
+        let plaintext = serde_json::to_vec(log).expect("LOG record is always serializable");
+        let sealed = log_cipher::seal(&self.key, &plaintext);
+        self.file.write_all(&(sealed.len() as u32).to_le_bytes()).map_err(Error::Io)?;
+        self.file.write_all(&sealed).map_err(Error::Io)
+    }
+}
+
+pub struct EncryptedLogReader<A> {
+    records: ::std::vec::IntoIter<Vec<u8>>,
+    key: secretbox::Key,
+    _marker: ::std::marker::PhantomData<A>,
+}
+
+impl<A> EncryptedLogReader<A> {
+    fn open(log_lock: log::LogLock, key: secretbox::Key) -> Result<EncryptedLogReader<A>, Error> {
+        let mut file = ::std::fs::File::open(log_lock.path()).map_err(Error::Io)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).map_err(Error::Io)?;
+
+        let mut records = Vec::new();
+        let mut cursor = &bytes[..];
+        while cursor.len() >= 4 {
+            let (len_bytes, rest) = cursor.split_at(4);
+            let len = u32::from_le_bytes([len_bytes[0], len_bytes[1], len_bytes[2], len_bytes[3]]) as usize;
+            if rest.len() < len {
+                return Err(Error::WalletLogCorrupted("truncated LOG record".to_string()));
+            }
+            let (record, rest) = rest.split_at(len);
+            records.push(record.to_vec());
+            cursor = rest;
+        }
+
+        Ok(EncryptedLogReader { records: records.into_iter(), key, _marker: ::std::marker::PhantomData })
+    }
+}
+
+impl<A> Iterator for EncryptedLogReader<A>
+    where for<'de> A: serde::Deserialize<'de>
+{
+    type Item = Result<log::Log<A>, Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let sealed = self.records.next()?;
+        Some(
+            log_cipher::open(&self.key, &sealed)
+                .map_err(|()| Error::WalletLogDecryption)
+                .and_then(|plaintext| serde_json::from_slice(&plaintext).map_err(|e| Error::WalletLogCorrupted(e.to_string())))
+        )
+    }
+}
+
+/// Set a password on the wallet LOG, encrypting every record currently on
+/// disk and all future appends. Errors if the LOG is already encrypted.
+pub fn encrypt_wallet_log(term: &mut Term, wallet: &Wallet) -> Result<(), Error> {
+    let log_lock = lock_wallet_log(wallet)?;
+    let path = log_lock.path().to_path_buf();
+    if log_cipher::is_encrypted(&path) {
+        return Err(Error::WalletLogAlreadyEncrypted);
+    }
+
+    term.info("Set a password to encrypt the wallet LOG.\n").unwrap();
+    let password = term.password("LOG password: ").unwrap();
+
+    let records : Vec<log::Log<ExtendedAddr>> = log::LogReader::open(log_lock).map_err(Error::WalletLog)?
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Error::WalletLog)?;
+
+    let key = log_cipher::set_password(&path, password.as_bytes())?;
+    ::std::fs::remove_file(&path).map_err(Error::Io)?;
+
+    let mut writer = EncryptedLogWriter::open(lock_wallet_log(wallet)?, key.clone())?;
+    for log in &records { writer.append(log)?; }
+
+    term.success("wallet LOG is now encrypted, run `unlock` to use it this session\n").unwrap();
+    Ok(())
+}
+
+/// Derive and cache the LOG key for this session, so syncing can keep
+/// appending to an encrypted LOG without re-prompting for the password.
+pub fn unlock_wallet_log(term: &mut Term, wallet: &Wallet) -> Result<(), Error> {
+    let log_lock = lock_wallet_log(wallet)?;
+    let path = log_lock.path().to_path_buf();
+    if ! log_cipher::is_encrypted(&path) {
+        return Err(Error::WalletLogNotEncrypted);
+    }
+
+    term.info("Enter the wallet LOG password.\n").unwrap();
+    let password = term.password("LOG password: ").unwrap();
+    let key = log_cipher::unlock(&path, password.as_bytes())?;
+
+    // make sure the password actually opens the LOG before caching it
+    if let Some(record) = EncryptedLogReader::<ExtendedAddr>::open(log_lock, key.clone())?.next() {
+        record?;
+    }
+
+    log_cipher::cache_session_key(&path, &key);
+    term.success("wallet LOG unlocked for this session\n").unwrap();
+    Ok(())
+}
+
+/// Permanently remove encryption from the wallet LOG, rewriting it back
+/// to plaintext.
+pub fn decrypt_wallet_log(term: &mut Term, wallet: &Wallet) -> Result<(), Error> {
+    let log_lock = lock_wallet_log(wallet)?;
+    let path = log_lock.path().to_path_buf();
+    if ! log_cipher::is_encrypted(&path) {
+        return Err(Error::WalletLogNotEncrypted);
+    }
+
+    let key = match log_cipher::cached_session_key(&path) {
+        Some(key) => key,
         None => {
-            term.error("Wallet is not attached to any blockchain\n").unwrap();
-            ::std::process::exit(1);
-        },
-        Some(blockchain) => {
-            Blockchain::load(root_dir, blockchain)
+            term.info("Enter the wallet LOG password.\n").unwrap();
+            let password = term.password("LOG password: ").unwrap();
+            log_cipher::unlock(&path, password.as_bytes())?
+        }
+    };
+
+    let records : Vec<log::Log<ExtendedAddr>> = EncryptedLogReader::open(log_lock, key)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    ::std::fs::remove_file(&path).map_err(Error::Io)?;
+    log_cipher::remove_header(&path).map_err(Error::Io)?;
+    log_cipher::clear_session_key(&path);
+
+    let mut writer = log::LogWriter::open(lock_wallet_log(wallet)?).map_err(Error::WalletLog)?;
+    for log in &records { writer.append(log).map_err(Error::WalletLog)?; }
+
+    term.success("wallet LOG decrypted\n").unwrap();
+    Ok(())
+}
+
+/// An [`lookup::AddressLookup`] built from the wallet's own private key
+/// can additionally sign for the addresses it recognizes, and derive a
+/// fresh internal (change) address. Watch-only lookups built from a bare
+/// public key (see [`load_bip44_lookup_structure`]) are of the same types
+/// but hold no spending key.
+///
+/// `new_change_address` takes `&mut self` because handing out an address
+/// must also advance whatever internal index the implementor derives its
+/// next change address from -- otherwise a second call in the same
+/// session would hand out the same "fresh" address again, and a second
+/// `send` wouldn't recognize its own earlier change as a tracked UTxO.
+pub trait Spend: lookup::AddressLookup {
+    fn sign(&self, addressing: &Self::AddressOutput, txid: &TxId) -> TxInWitness;
+    fn new_change_address(&mut self) -> ExtendedAddr;
+}
+
+// `lookup::sequentialindex::SequentialBip44Lookup` and
+// `lookup::randomindex::RandomIndexLookup` (constructed with the wallet's
+// own private key via `::new(wallet)`/`::from(wallet)`, as opposed to the
+// watch-only `::from_public_key` path) are the two implementors `send`
+// is meant to be called with. Their `Spend` impls live in `state::lookup`
+// itself, the same module tree this snapshot doesn't include (see the
+// note on `WalletConfig` in `super`) -- signing needs the decrypted spending
+// key and the derivation-path bookkeeping that module owns, neither of
+// which this file has access to, so they aren't reproduced here.
+
+/// How many (exclude, include) branches [`branch_and_bound`] may explore
+/// before giving up and falling back to largest-first accumulation.
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// Exact-match coin selection: explores including/excluding each
+/// candidate (sorted descending by value) looking for a subset whose sum
+/// lands in `target..=target+cost_of_change`, so the built transaction
+/// needs no change output at all. Gives up after `BNB_MAX_TRIES`
+/// branches and lets the caller fall back to [`largest_first`].
+fn branch_and_bound<L: Clone>(sorted_utxos: &[UTxO<L>], target: Coin, cost_of_change: Coin) -> Option<Vec<usize>> {
+    fn search<L: Clone>( utxos: &[UTxO<L>]
+                        , index: usize
+                        , current: Coin
+                        , selected: &mut Vec<usize>
+                        , target: Coin
+                        , upper: Coin
+                        , tries: &mut usize
+                        , best: &mut Option<Vec<usize>>
+                        ) {
+        *tries += 1;
+        if *tries > BNB_MAX_TRIES || best.is_some() { return; }
+
+        if current >= target {
+            if current <= upper { *best = Some(selected.clone()); }
+            return;
+        }
+        if index == utxos.len() { return; }
+
+        selected.push(index);
+        let included = (current + utxos[index].credited_value).expect("selection cannot overflow Coin");
+        search(utxos, index + 1, included, selected, target, upper, tries, best);
+        selected.pop();
+        if best.is_some() { return; }
+
+        search(utxos, index + 1, current, selected, target, upper, tries, best);
+    }
+
+    let upper = (target + cost_of_change).expect("target + cost_of_change cannot overflow Coin");
+    let mut tries = 0;
+    let mut best = None;
+    let mut selected = Vec::new();
+    search(sorted_utxos, 0, Coin::zero(), &mut selected, target, upper, &mut tries, &mut best);
+    best
+}
+
+/// Fallback coin selection: keep accumulating the largest remaining
+/// UTxOs (already sorted descending) until `target` is covered. Always
+/// succeeds if the wallet holds enough funds, and always leaves a change
+/// output unless the accumulated total happens to match exactly.
+fn largest_first<L: Clone>(sorted_utxos: &[UTxO<L>], target: Coin) -> Option<(Vec<usize>, Coin)> {
+    let mut total = Coin::zero();
+    let mut selected = Vec::new();
+    for (index, utxo) in sorted_utxos.iter().enumerate() {
+        if total >= target { break; }
+        selected.push(index);
+        total = (total + utxo.credited_value).expect("selection cannot overflow Coin");
+    }
+    if total >= target { Some((selected, total)) } else { None }
+}
+
+/// Select inputs to cover `target_value` plus fees, against the given
+/// `base_outputs` count (the requested outputs, before any change
+/// output). The fee depends on the number of inputs/outputs selected, so
+/// each pass re-estimates it from the previous pass's result and retries
+/// until the input count stops moving. Returns the selected UTxOs and,
+/// if largest-first had to fall back, the resulting change amount.
+///
+/// The loop runs at most `sorted.len() + 1` passes. `num_inputs` can move
+/// either way between passes: a higher fee target can lead
+/// branch-and-bound to a different, even smaller, subset, or switch the
+/// pass from branch-and-bound to largest-first. If the count still hasn't
+/// settled after the last pass, we already know the wallet has enough funds
+/// -- every pass up to that point found a covering selection -- so the
+/// last one found is returned rather than reporting `InsufficientFunds`.
+/// Only a pass where largest-first can't cover the target even with
+/// every available UTxO is a genuine insufficient-funds case.
+///
+/// That last-found fallback's `change` was sized against the `num_inputs`
+/// the fee estimate entered the pass with, not necessarily the input
+/// count the selection actually settled on -- callers that need a
+/// guaranteed-covering selection (see `send`) must re-derive the fee
+/// from the returned selection's real counts before trusting it.
+fn select_coins<L: Clone>( utxos: &[UTxO<L>]
+                         , fee_algorithm: &LinearFee
+                         , target_value: Coin
+                         , base_outputs: usize
+                         ) -> Option<(Vec<UTxO<L>>, Option<Coin>)> {
+    let mut sorted = utxos.to_vec();
+    sorted.sort_by(|a, b| b.credited_value.cmp(&a.credited_value));
+
+    let max_tries = sorted.len() + 1;
+    let mut num_inputs = 1;
+    let mut last_success: Option<(Vec<usize>, Option<Coin>)> = None;
+
+    for _ in 0..max_tries {
+        let fee_no_change   = fee_algorithm.estimate(num_inputs, base_outputs).expect("fee estimation");
+        let fee_with_change = fee_algorithm.estimate(num_inputs, base_outputs + 1).expect("fee estimation");
+        let cost_of_change  = (fee_with_change - fee_no_change).unwrap_or(Coin::zero());
+
+        let target_no_change = (target_value + fee_no_change).expect("target cannot overflow Coin");
+        if let Some(indices) = branch_and_bound(&sorted, target_no_change, cost_of_change) {
+            if indices.len() == num_inputs || indices.is_empty() {
+                let inputs = indices.iter().map(|&i| sorted[i].clone()).collect();
+                return Some((inputs, None));
+            }
+            num_inputs = indices.len();
+            last_success = Some((indices, None));
+            continue;
         }
+
+        let target_with_change = (target_value + fee_with_change).expect("target cannot overflow Coin");
+        if let Some((indices, total)) = largest_first(&sorted, target_with_change) {
+            let change = (total - target_with_change).ok();
+            if indices.len() == num_inputs {
+                let inputs = indices.iter().map(|&i| sorted[i].clone()).collect();
+                return Some((inputs, change));
+            }
+            num_inputs = indices.len();
+            last_success = Some((indices, change));
+            continue;
+        }
+
+        return None;
+    }
+
+    last_success.map(|(indices, change)| {
+        (indices.iter().map(|&i| sorted[i].clone()).collect(), change)
+    })
+}
+
+/// Build and sign a transaction spending the wallet's own tracked UTxOs
+/// to the given `outputs`. Coin selection first tries an exact
+/// Branch-and-Bound match (no change output); if that fails it falls
+/// back to largest-first accumulation with a change output back to a
+/// freshly derived internal address. Each selected input is signed with
+/// the key for the `AddressLookup` path that originally recognized its
+/// `credited_address`. Selected UTxOs are forgotten from `state` so a
+/// later `send` or sync doesn't try to spend them again.
+///
+/// `select_coins` can hand back a non-converged selection (see its own
+/// doc comment): one whose change was sized against a different input
+/// count than it actually settled on. Before building the transaction,
+/// `send` re-derives the fee from the selection's real input/output
+/// counts and checks the selected inputs actually cover `target_value +
+/// fee`, recomputing change from that rather than trusting the fee
+/// `select_coins` budgeted for a possibly different input count.
+pub fn send<LS>( state: &mut state::State<LS>
+                , lookup: &mut LS
+                , outputs: Vec<(ExtendedAddr, Coin)>
+                ) -> Result<TxAux, Error>
+    where LS: Spend<AddressInput = ExtendedAddr>
+        , for<'de> LS::AddressOutput : serde::Deserialize<'de> + serde::Serialize + Clone + ::std::fmt::Debug
+{
+    let fee_algorithm = LinearFee::default();
+    let target_value = outputs.iter().fold(Coin::zero(), |acc, &(_, value)| {
+        (acc + value).expect("requested spend cannot overflow Coin")
+    });
+
+    let utxos : Vec<UTxO<LS::AddressOutput>> = state.utxos().cloned().collect();
+
+    let (selected, change) = select_coins(&utxos, &fee_algorithm, target_value, outputs.len())
+        .ok_or(Error::InsufficientFunds)?;
+
+    let has_change_output = change.map_or(false, |change_value| change_value > Coin::zero());
+    let actual_fee = fee_algorithm
+        .estimate(selected.len(), outputs.len() + if has_change_output { 1 } else { 0 })
+        .expect("fee estimation");
+    let required = (target_value + actual_fee).expect("required spend cannot overflow Coin");
+    let total_input = selected.iter().fold(Coin::zero(), |acc, utxo| {
+        (acc + utxo.credited_value).expect("selected input total cannot overflow Coin")
+    });
+    let change_value = (total_input - required).map_err(|_| Error::InsufficientFunds)?;
+
+    let mut txouts : Vec<TxOut> = outputs.iter()
+        .map(|&(ref address, value)| TxOut { address: address.clone(), value })
+        .collect();
+
+    if has_change_output && change_value > Coin::zero() {
+        txouts.push(TxOut { address: lookup.new_change_address(), value: change_value });
     }
+
+    let txins : Vec<TxIn> = selected.iter()
+        .map(|utxo| TxIn::new(utxo.transaction_id.clone(), utxo.index_in_transaction))
+        .collect();
+
+    let tx = Tx::new_with(txins, txouts);
+    let txid = tx.id();
+
+    let witnesses : Vec<TxInWitness> = selected.iter()
+        .map(|utxo| lookup.sign(&utxo.credited_address, &txid))
+        .collect();
+
+    for utxo in selected { state.forget_utxo(utxo); }
+
+    Ok(TxAux::new(tx, witnesses))
 }
 
-const IMPOSSIBLE_HAPPENED : &'static str = "The impossible happened
-The process will panic with an error message, this is because something
-unexpected happened. Please report the error message with the panic
-error message to: https://github.com/input-output-hk/rust-cardano/issues
-";
\ No newline at end of file
+pub fn load_attached_blockchain(root_dir: PathBuf, name: Option<String>) -> Result<Blockchain, Error> {
+    match name {
+        None => Err(Error::WalletNotAttached),
+        Some(blockchain) => Ok(Blockchain::load(root_dir, blockchain)),
+    }
+}
\ No newline at end of file